@@ -1,24 +1,26 @@
-#![feature(iter_array_chunks)]
-#![feature(iter_intersperse)]
-
+use addr2line::gimli;
+use addr2line::object;
+use addr2line::object::Object;
 use anyhow::Result;
 use elf::endian::AnyEndian;
 use elf::ElfBytes;
 use elf::ParseError;
+use inferno::flamegraph;
+use inferno::flamegraph::color::{BasicPalette, Palette};
 use rustc_demangle::demangle;
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::BufWriter;
 use std::io::Read;
-use std::io::Write;
 use std::io::{BufReader, Bytes};
 use std::mem::size_of;
-use std::process::{exit, Command, Stdio};
+use std::process::exit;
 
 struct Symbol {
     addr: u64,
@@ -52,6 +54,101 @@ fn list_symbols(elf_path: &OsString) -> Result<Option<Vec<Symbol>>> {
     Ok(Some(syms))
 }
 
+/// Parses a standalone symbols listing, sorted by address.
+///
+/// This is used as a fallback (or augmentation) when the ELF itself carries no `.symtab`, which
+/// is typically the case for stripped release kernels whose symbols are instead recorded in a
+/// `symbols.txt` file generated alongside the build (this is *not* the columnar format emitted by
+/// `ld -Map`, which this function does not understand).
+///
+/// Each non-empty line is expected to have the form `name = address (size)`, where both `address`
+/// and `size` are hexadecimal (an optional `0x` prefix is accepted on either), and `(size)` is
+/// optional and defaults to `0` when absent. Non-empty lines that do not match this form are
+/// skipped, and a warning with the number of skipped lines is printed to stderr so a
+/// mismatched-format file doesn't silently yield an empty symbol set.
+fn parse_symbols_file(path: &OsString) -> Result<Vec<Symbol>> {
+    let content = fs::read_to_string(path)?;
+    let mut syms = Vec::new();
+    let mut unparsed = 0;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, rest)) = line.split_once('=') else {
+            unparsed += 1;
+            continue;
+        };
+        let name = name.trim().to_string();
+        let rest = rest.trim();
+        let (addr_str, size_str) = match rest.split_once('(') {
+            Some((addr, size)) => (addr.trim(), size.trim_end_matches(')').trim()),
+            None => (rest, "0"),
+        };
+        let Ok(addr) = u64::from_str_radix(addr_str.trim_start_matches("0x"), 16) else {
+            unparsed += 1;
+            continue;
+        };
+        let size_str = size_str.trim_start_matches("0x");
+        let size = if size_str.is_empty() {
+            Ok(0)
+        } else {
+            u64::from_str_radix(size_str, 16)
+        };
+        let Ok(size) = size else {
+            unparsed += 1;
+            continue;
+        };
+        syms.push(Symbol { addr, size, name });
+    }
+    if unparsed > 0 {
+        eprintln!(
+            "warning: {} line(s) in {:?} did not match the `name = address (size)` format and were skipped",
+            unparsed, path
+        );
+    }
+    syms.sort_unstable_by(|s1, s2| s1.addr.cmp(&s2.addr).then_with(|| s1.size.cmp(&s2.size)));
+    Ok(syms)
+}
+
+/// Merges symbols found in the ELF's symbol table with symbols coming from an external listing
+/// (see [`parse_symbols_file`]), returning the result sorted by address as required by
+/// [`find_symbol`].
+///
+/// An ELF entry always takes precedence over an external one at the same address. Otherwise, when
+/// several symbols from the same source share an address (aliases are common for zero-size
+/// labels), the one with the largest `size` is kept, since it covers the widest
+/// `[addr, addr+size)` range for [`find_symbol`]'s lookup.
+fn merge_symbols(elf_syms: Vec<Symbol>, extra_syms: Vec<Symbol>) -> Vec<Symbol> {
+    let mut by_addr: HashMap<u64, (Symbol, bool)> = HashMap::new();
+    for sym in extra_syms {
+        if let Some((existing, _)) = by_addr.get(&sym.addr) {
+            if existing.size > sym.size {
+                continue;
+            }
+        }
+        by_addr.insert(sym.addr, (sym, false));
+    }
+    for sym in elf_syms {
+        by_addr
+            .entry(sym.addr)
+            .and_modify(|(existing, from_elf)| {
+                if sym.size >= existing.size || !*from_elf {
+                    *existing = Symbol {
+                        addr: sym.addr,
+                        size: sym.size,
+                        name: sym.name.clone(),
+                    };
+                    *from_elf = true;
+                }
+            })
+            .or_insert((sym, true));
+    }
+    let mut merged: Vec<Symbol> = by_addr.into_values().map(|(sym, _)| sym).collect();
+    merged.sort_unstable_by(|s1, s2| s1.addr.cmp(&s2.addr).then_with(|| s1.size.cmp(&s2.size)));
+    merged
+}
+
 /// Returns the name of the symbol in which the address is located.
 fn find_symbol(symbols: &[Symbol], addr: u64) -> Option<&str> {
     let index = symbols
@@ -68,49 +165,353 @@ fn find_symbol(symbols: &[Symbol], addr: u64) -> Option<&str> {
     Some(symbols[index].name.as_str())
 }
 
-/// TODO doc
-fn stack_iter<'i, 's: 'i, I: Iterator<Item = io::Result<u8>>>(
-    iter: &'i mut I,
+/// A single resolved stack frame.
+///
+/// DWARF-based resolution can yield several [`Frame`]s for a single sampled address, one per
+/// function inlined at that address, ordered innermost first (the physical, non-inlined function
+/// comes last).
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct Frame {
+    /// Demangled function name.
+    name: String,
+    /// Source file and line, when debug info provides one.
+    location: Option<(String, u32)>,
+}
+
+impl Frame {
+    /// The frame shown when an address cannot be resolved at all.
+    fn unknown() -> Self {
+        Self {
+            name: "???".to_string(),
+            location: None,
+        }
+    }
+
+    /// Returns the label under which this frame should appear in the folded stack.
+    fn label(&self) -> String {
+        match &self.location {
+            Some((file, line)) => format!("{} ({file}:{line})", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Returns the `[start, end)` virtual-address ranges covered by the ELF's `PT_LOAD` segments, as
+/// recorded at link time.
+fn load_segments(elf_path: &OsString) -> Result<Vec<(u64, u64)>> {
+    let elf_buf = fs::read(elf_path)?;
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(&elf_buf)?;
+    let segments = elf
+        .segments()
+        .ok_or_else(|| anyhow::anyhow!("ELF has no program headers"))?;
+    Ok(segments
+        .iter()
+        .filter(|seg| seg.p_type == elf::abi::PT_LOAD)
+        .map(|seg| (seg.p_vaddr, seg.p_vaddr + seg.p_memsz))
+        .collect())
+}
+
+type Addr2LineContext = addr2line::Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>;
+
+/// Builds the DWARF symbolication context from the ELF's `.debug_*` sections.
+///
+/// Returns `None` if the ELF carries no debug info, in which case callers should fall back to
+/// [`find_symbol`].
+fn build_dwarf_context(elf_path: &OsString) -> Result<Option<Addr2LineContext>> {
+    let elf_buf = fs::read(elf_path)?;
+    let object = object::File::parse(&*elf_buf)?;
+    if object.section_by_name(".debug_info").is_none() {
+        return Ok(None);
+    }
+    Ok(Some(addr2line::Context::new(&object)?))
+}
+
+/// Resolves sampled addresses to resolved [`Frame`]s, using DWARF inline info when available and
+/// falling back to the flat ELF/external symbol table otherwise.
+///
+/// Sampled addresses are expected to be link-time virtual addresses unless a load bias is set
+/// (see [`Symbolicator::new`]), in which case it is subtracted from every incoming address before
+/// any lookup, to account for KASLR / PIE relocation.
+struct Symbolicator<'s> {
     symbols: &'s [Symbol],
-) -> io::Result<impl Iterator<Item = Option<&'s str>> + 'i> {
-    let Some(stack_depth) = iter.next().transpose()? else {
-        // TODO
-        todo!()
-    };
-    let stack_depth = stack_depth as usize;
-    Ok(iter
-        .take(stack_depth * size_of::<u64>())
-        .map(|r| r.unwrap()) // TODO handle error
-        .array_chunks()
-        .map(u64::from_le_bytes)
-        .map(|addr| find_symbol(symbols, addr)))
+    dwarf: Option<Addr2LineContext>,
+    /// Delta between the runtime base (as recorded by the profiler) and the link-time base, to
+    /// subtract from sampled addresses before lookup. Zero when no `--load-offset` was given.
+    load_bias: u64,
+    /// Link-time `[start, end)` ranges of the ELF's `PT_LOAD` segments, used to tell a bad
+    /// `--load-offset` apart from genuinely unknown code.
+    segments: Vec<(u64, u64)>,
+    /// Number of adjusted addresses that fell outside every known `PT_LOAD` range.
+    out_of_range: Cell<u64>,
+}
+
+impl<'s> Symbolicator<'s> {
+    fn new(elf_path: &OsString, symbols: &'s [Symbol], load_offset: Option<u64>) -> Result<Self> {
+        let segments = load_segments(elf_path)?;
+        let link_base = segments.iter().map(|(start, _)| *start).min().unwrap_or(0);
+        let load_bias = load_offset
+            .map(|runtime_base| runtime_base.wrapping_sub(link_base))
+            .unwrap_or(0);
+        Ok(Self {
+            symbols,
+            dwarf: build_dwarf_context(elf_path)?,
+            load_bias,
+            segments,
+            out_of_range: Cell::new(0),
+        })
+    }
+
+    /// Number of sampled addresses that, once adjusted for the load bias, fell outside every
+    /// known `PT_LOAD` range.
+    fn out_of_range_count(&self) -> u64 {
+        self.out_of_range.get()
+    }
+
+    /// Resolves `addr` to the full inlined call chain, outermost caller last.
+    ///
+    /// Returns `None` if the address cannot be resolved by any known means, so that callers can
+    /// tell genuinely unknown addresses apart from resolved ones (see [`fold_stacks_cpu`]'s
+    /// substack splitting).
+    fn resolve(&self, addr: u64) -> Option<Vec<Frame>> {
+        let addr = addr.wrapping_sub(self.load_bias);
+        if self.load_bias != 0
+            && !self
+                .segments
+                .iter()
+                .any(|(start, end)| addr >= *start && addr < *end)
+        {
+            self.out_of_range.set(self.out_of_range.get() + 1);
+        }
+        if let Some(dwarf) = &self.dwarf {
+            if let Ok(mut frames) = dwarf.find_frames(addr).skip_all_loads() {
+                let mut resolved = Vec::new();
+                while let Ok(Some(frame)) = frames.next() {
+                    let name = frame
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.demangle().ok().map(|n| n.into_owned()))
+                        .unwrap_or_else(|| "???".to_string());
+                    let location = frame
+                        .location
+                        .as_ref()
+                        .and_then(|loc| Some((loc.file?.to_string(), loc.line?)));
+                    resolved.push(Frame { name, location });
+                }
+                if !resolved.is_empty() {
+                    return Some(resolved);
+                }
+            }
+        }
+        find_symbol(self.symbols, addr).map(|name| {
+            vec![Frame {
+                name: name.to_string(),
+                location: None,
+            }]
+        })
+    }
+}
+
+/// Magic bytes the profiler writes at the start of every profile file.
+const MAGIC: &[u8; 4] = b"KPRF";
+/// Profile file format version understood by this build.
+const VERSION: u8 = 1;
+
+/// Errors arising while parsing a profile file's binary format.
+///
+/// These always indicate a malformed or unsupported file, as opposed to a [`io::Error`] coming
+/// from the underlying reader itself.
+#[derive(Debug)]
+enum ProfileError {
+    /// The stream ended partway through a record.
+    Truncated,
+    /// The file does not start with [`MAGIC`].
+    BadMagic,
+    /// The file's header names a version this build does not understand.
+    UnsupportedVersion(u8),
+    /// An allocator-tracing record carried an opcode this tool does not know.
+    InvalidOpcode(u8),
+    /// A string field was not valid UTF-8.
+    BadUtf8,
+    /// A LEB128 varint carried more continuation bytes than fit in a `u64`.
+    InvalidVarint,
 }
 
-/// TODO doc
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "profile stream ended partway through a record"),
+            Self::BadMagic => write!(f, "file does not start with the expected magic bytes"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported profile format version {v}"),
+            Self::InvalidOpcode(op) => write!(f, "invalid allocator opcode `{op}`"),
+            Self::BadUtf8 => write!(f, "allocator name is not valid UTF-8"),
+            Self::InvalidVarint => {
+                write!(f, "varint has more continuation bytes than fit in a u64")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<ProfileError> for io::Error {
+    /// Truncation is reported as [`io::ErrorKind::UnexpectedEof`] so that callers parsing a
+    /// stream of records can tell a clean (or mid-record) end of file apart from genuine
+    /// corruption, and fold what was fully recorded instead of aborting (see [`eof_or`]).
+    fn from(e: ProfileError) -> Self {
+        let kind = match e {
+            ProfileError::Truncated => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Reads and validates the profile file's magic + version header.
+fn read_header<I: Iterator<Item = io::Result<u8>>>(iter: &mut I) -> io::Result<()> {
+    let mut magic = [0u8; MAGIC.len()];
+    for byte in &mut magic {
+        *byte = iter.next().transpose()?.ok_or(ProfileError::Truncated)?;
+    }
+    if &magic != MAGIC {
+        return Err(ProfileError::BadMagic.into());
+    }
+    let version = iter.next().transpose()?.ok_or(ProfileError::Truncated)?;
+    if version != VERSION {
+        return Err(ProfileError::UnsupportedVersion(version).into());
+    }
+    Ok(())
+}
+
+/// Turns an `UnexpectedEof`-kind error into a clean `Ok(None)`.
+///
+/// A profiler killed mid-write leaves a stream that ends partway through a record; callers treat
+/// that the same as a clean end of stream, folding whatever complete records came before instead
+/// of aborting.
+fn eof_or<T>(result: io::Result<Option<T>>) -> io::Result<Option<T>> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        other => other,
+    }
+}
+
+/// Reads a little-endian `u64` from the stream.
+///
+/// Returns `Ok(None)` if the stream is cleanly exhausted before any byte of the value is read.
 fn next_u64<I: Iterator<Item = io::Result<u8>>>(iter: &mut I) -> io::Result<Option<u64>> {
-    Ok(iter
-        .map(|r| r.unwrap()) // TODO handle error
-        .array_chunks()
-        .map(u64::from_le_bytes)
-        .next())
+    let Some(first) = iter.next().transpose()? else {
+        return Ok(None);
+    };
+    let mut buf = [0u8; size_of::<u64>()];
+    buf[0] = first;
+    for byte in &mut buf[1..] {
+        *byte = iter.next().transpose()?.ok_or(ProfileError::Truncated)?;
+    }
+    Ok(Some(u64::from_le_bytes(buf)))
+}
+
+/// Reads an unsigned LEB128-encoded integer from the stream.
+///
+/// Returns `Ok(None)` if the stream is cleanly exhausted before any byte is read.
+fn next_uleb128<I: Iterator<Item = io::Result<u8>>>(iter: &mut I) -> io::Result<Option<u64>> {
+    let Some(mut byte) = iter.next().transpose()? else {
+        return Ok(None);
+    };
+    let mut result = (byte & 0x7f) as u64;
+    let mut shift = 7;
+    while byte & 0x80 != 0 {
+        if shift >= 64 {
+            return Err(ProfileError::InvalidVarint.into());
+        }
+        byte = iter.next().transpose()?.ok_or(ProfileError::Truncated)?;
+        result |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+    }
+    Ok(Some(result))
+}
+
+/// Caps how many frames of a single sampled stack are kept, guarding against a corrupt (or
+/// legitimately huge) recorded depth causing an oversized allocation.
+struct DepthLimiter {
+    max_depth: usize,
+    /// Number of stacks that recorded more frames than `max_depth` and were truncated.
+    clamped_stacks: Cell<u64>,
+}
+
+impl DepthLimiter {
+    fn new(max_depth: usize) -> Self {
+        Self {
+            max_depth,
+            clamped_stacks: Cell::new(0),
+        }
+    }
+
+    fn clamped_stacks(&self) -> u64 {
+        self.clamped_stacks.get()
+    }
 }
 
-type FoldedStacks<'s> = HashMap<Vec<&'s str>, u64>;
+/// Reads and resolves one sampled stack.
+///
+/// Returns `Ok(None)` when the stream is exhausted before a new stack's frames begin, including when
+/// it ends partway through the depth prefix. A stack cut
+/// short partway through its frames (e.g. by a profiler killed mid-write) yields the frames that
+/// were fully recorded instead of an error. A stack recording more than `depth_limiter`'s
+/// `max_depth` frames is truncated to that many (see [`DepthLimiter`]); the remaining recorded
+/// frames are still consumed from the stream so that later records stay in sync.
+fn stack_iter<I: Iterator<Item = io::Result<u8>>>(
+    iter: &mut I,
+    symbolicator: &Symbolicator,
+    depth_limiter: &DepthLimiter,
+) -> io::Result<Option<Vec<Option<Vec<Frame>>>>> {
+    let Some(stack_depth) = eof_or(next_uleb128(iter))? else {
+        return Ok(None);
+    };
+    let stack_depth = stack_depth as usize;
+    let kept_depth = stack_depth.min(depth_limiter.max_depth);
+    if kept_depth < stack_depth {
+        depth_limiter
+            .clamped_stacks
+            .set(depth_limiter.clamped_stacks.get() + 1);
+    }
+    let mut frames = Vec::with_capacity(kept_depth);
+    for _ in 0..kept_depth {
+        let Some(addr) = eof_or(next_u64(iter))? else {
+            return Ok(Some(frames));
+        };
+        frames.push(symbolicator.resolve(addr));
+    }
+    // Consume (without keeping) any frames beyond the cap, so the stream stays in sync.
+    for _ in kept_depth..stack_depth {
+        let Some(_) = eof_or(next_u64(iter))? else {
+            break;
+        };
+    }
+    Ok(Some(frames))
+}
+
+type FoldedStacks = HashMap<Vec<Frame>, u64>;
 
 /// Count the number of identical stacks.
 ///
 /// The function returns a hashmap with each stack associated with its number of occurrences.
-fn fold_stacks_cpu(iter: Bytes<BufReader<File>>, symbols: &[Symbol]) -> io::Result<FoldedStacks> {
+fn fold_stacks_cpu(
+    iter: Bytes<BufReader<File>>,
+    symbolicator: &Symbolicator,
+    depth_limiter: &DepthLimiter,
+) -> io::Result<FoldedStacks> {
     let mut iter = iter.peekable();
     let mut folded_stacks: FoldedStacks = HashMap::new();
     while iter.peek().is_some() {
-        let mut frames = stack_iter(&mut iter, symbols)?.peekable();
+        let Some(stack) = stack_iter(&mut iter, symbolicator, depth_limiter)? else {
+            break;
+        };
+        let mut frames = stack.into_iter().peekable();
         // Subdivide stack into substacks (interruptions handling)
         while frames.peek().is_some() {
             let substack: Vec<_> = frames
                 .by_ref()
                 .take_while(Option::is_some)
-                .map(|f| f.unwrap())
+                .flat_map(|f| f.unwrap())
                 .collect();
             if substack.is_empty() {
                 continue;
@@ -127,38 +528,46 @@ fn fold_stacks_cpu(iter: Bytes<BufReader<File>>, symbols: &[Symbol]) -> io::Resu
 /// For each allocator, the function returns a hashmap with each stack associated with the quantity of allocated memory.
 fn fold_stacks_memory(
     iter: Bytes<BufReader<File>>,
-    symbols: &[Symbol],
+    symbolicator: &Symbolicator,
+    depth_limiter: &DepthLimiter,
 ) -> io::Result<HashMap<String, FoldedStacks>> {
     let mut iter = iter.peekable();
-    let mut allocators: HashMap<String, HashMap<u64, (Vec<&str>, u64)>> = HashMap::new();
+    let mut allocators: HashMap<String, HashMap<u64, (Vec<Frame>, u64)>> = HashMap::new();
     while let Some(alloc_name_len) = iter.next() {
         let alloc_name_len = alloc_name_len? as usize;
-        let name = iter
+        let name_bytes: Vec<u8> = iter
             .by_ref()
             .take(alloc_name_len)
-            .map(|c| c.map(char::from))
-            .collect::<io::Result<String>>()?;
-        let Some(op) = iter.next().transpose()? else {
+            .collect::<io::Result<_>>()?;
+        if name_bytes.len() < alloc_name_len {
+            break;
+        }
+        let name = String::from_utf8(name_bytes).map_err(|_| ProfileError::BadUtf8)?;
+        let Some(op) = eof_or(iter.next().transpose())? else {
+            break;
+        };
+        let Some(ptr) = eof_or(next_u64(&mut iter))? else {
             break;
         };
-        let Some(ptr) = next_u64(&mut iter)? else {
+        let Some(size) = eof_or(next_u64(&mut iter))? else {
             break;
         };
-        let Some(size) = next_u64(&mut iter)? else {
+        let Some(stack) = stack_iter(&mut iter, symbolicator, depth_limiter)? else {
             break;
         };
-        let frames = stack_iter(&mut iter, symbols)?
-            .map(|s| s.unwrap_or("???"))
+        let frames = stack
+            .into_iter()
+            .flat_map(|f| f.unwrap_or_else(|| vec![Frame::unknown()]))
             .collect();
         // Update
-        let entry = allocators.entry(name).or_insert(HashMap::new());
+        let entry = allocators.entry(name).or_default();
         let alloc = entry.entry(ptr).or_insert((frames, 0));
         match op {
             // Allocate or reallocate
             0 | 1 => alloc.1 = size,
             // Free
             2 => alloc.1 = 0,
-            opcode => panic!("Invalid opcode `{opcode}`"),
+            opcode => return Err(ProfileError::InvalidOpcode(opcode).into()),
         }
     }
     // Fold stacks
@@ -174,73 +583,173 @@ fn fold_stacks_memory(
         .collect())
 }
 
+/// Serializes a folded-stack map into the collapsed-stack text format consumed by flamegraph
+/// renderers: one `frame;frame;...;frame count` line per stack, frames written outermost first.
+fn serialize_folded_stacks(stacks: &FoldedStacks) -> Vec<String> {
+    stacks
+        .iter()
+        .map(|(frames, count)| {
+            let folded = frames
+                .iter()
+                .rev()
+                .map(Frame::label)
+                .collect::<Vec<_>>()
+                .join(";");
+            format!("{folded} {count}")
+        })
+        .collect()
+}
+
+/// Prints usage information and exits with a non-zero status.
+fn usage() -> ! {
+    eprintln!("usage: kern-profile [--alloc] [--stdout] [--symbols <path>] [--load-offset <hex>] [--max-depth <n>] <profile file> <elf file>");
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("\t--alloc: if set, the provided profile file contains memory allocator tracing. If not, it contains CPU tracing");
+    eprintln!("\t--stdout: if set, write the folded (collapsed) stacks to stdout instead of rendering SVG flamegraphs");
+    eprintln!("\t--symbols <path>: path to a symbols.txt listing `name = address (size)` entries, used as a fallback (or augmentation) when the ELF has no symbol table");
+    eprintln!("\t--load-offset <hex> (alias: --kaslr-base): runtime base address the profiler recorded for the kernel image, used to correct for KASLR/PIE relocation");
+    eprintln!("\t--max-depth <n>: maximum number of frames kept per sampled stack, to guard against a corrupt or unreasonably deep recording (default: 1024)");
+    eprintln!("\t<profile file>: path to the file containing samples recorded from execution");
+    eprintln!("\t<elf file>: path to the observed kernel");
+    eprintln!();
+    eprintln!("On success, the command writes one or several Flamegraph(s) at `cpu.svg` for CPU tracing, or at `mem-<allocator>.svg` for memory tracing, unless `--stdout` is set.");
+    exit(1);
+}
+
 fn main() -> io::Result<()> {
-    let mut args_iter = env::args_os().peekable();
+    let mut args_iter = env::args_os();
     // Skip program name
     args_iter.next();
-    let alloc = args_iter.next_if(|p| p == "--alloc").is_some();
-    let args: Vec<OsString> = args_iter.collect();
-    let [input_path, elf_path] = &args[..] else {
-        eprintln!("usage: kern-profile [--alloc] <profile file> <elf file>");
-        eprintln!();
-        eprintln!("options:");
-        eprintln!("\t--alloc: if set, the provided profile file contains memory allocator tracing. If not, it contains CPU tracing");
-        eprintln!("\t<profile file>: path to the file containing samples recorded from execution");
-        eprintln!("\t<elf file>: path to the observed kernel");
-        eprintln!();
-        eprintln!("On success, the command writes one or several Flamegraph(s) at `cpu.svg` for CPU tracing, or at `mem-<allocator>.svg` for memory tracing.");
-        exit(1);
+
+    let mut alloc = false;
+    let mut stdout_mode = false;
+    let mut symbols_path = None;
+    let mut load_offset = None;
+    let mut max_depth = None;
+    let mut positional = Vec::new();
+    // Flags are accepted in any order and may be interspersed with the positional arguments.
+    while let Some(arg) = args_iter.next() {
+        match arg.to_str() {
+            Some("--alloc") => alloc = true,
+            Some("--stdout") => stdout_mode = true,
+            Some("--symbols") => symbols_path = Some(args_iter.next().unwrap_or_else(|| usage())),
+            Some("--load-offset" | "--kaslr-base") => {
+                load_offset = Some(args_iter.next().unwrap_or_else(|| usage()))
+            }
+            Some("--max-depth") => max_depth = Some(args_iter.next().unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    let [input_path, elf_path] = &positional[..] else {
+        usage();
+    };
+    let load_offset = match load_offset
+        .map(|o| u64::from_str_radix(o.to_string_lossy().trim_start_matches("0x"), 16))
+        .transpose()
+    {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Invalid --load-offset: {e}");
+            exit(1);
+        }
+    };
+    let max_depth = match max_depth
+        .map(|n| n.to_string_lossy().parse::<usize>())
+        .transpose()
+    {
+        Ok(n) => n.unwrap_or(1024),
+        Err(e) => {
+            eprintln!("Invalid --max-depth: {e}");
+            exit(1);
+        }
     };
 
     // Read ELF symbols
-    let symbols = match list_symbols(elf_path) {
+    let elf_symbols = match list_symbols(elf_path) {
         Ok(s) => s,
         Err(e) => {
             eprintln!("Could not read ELF: {e}");
             exit(1);
         }
     };
-    let Some(symbols) = symbols else {
-        eprintln!("ELF does not have a symbol table!");
-        exit(1);
+    let extra_symbols = symbols_path.as_ref().map(parse_symbols_file).transpose();
+    let extra_symbols = match extra_symbols {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not read symbols file: {e}");
+            exit(1);
+        }
+    };
+    let symbols = match (elf_symbols, extra_symbols) {
+        (Some(elf_syms), Some(extra_syms)) => merge_symbols(elf_syms, extra_syms),
+        (Some(elf_syms), None) => elf_syms,
+        (None, Some(extra_syms)) => extra_syms,
+        (None, None) => {
+            eprintln!(
+                "ELF does not have a symbol table! Use `--symbols` to provide one separately."
+            );
+            exit(1);
+        }
+    };
+
+    let symbolicator = match Symbolicator::new(elf_path, &symbols, load_offset) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not read debug info: {e}");
+            exit(1);
+        }
     };
 
     // Read profile data
     let input = File::open(input_path)?;
     let reader = BufReader::new(input);
-    let iter = reader.bytes();
+    let mut iter = reader.bytes();
+    if let Err(e) = read_header(&mut iter) {
+        eprintln!("Could not read profile file: {e}");
+        exit(1);
+    }
+    let depth_limiter = DepthLimiter::new(max_depth);
     let graphs = if !alloc {
-        let folded_stacks = fold_stacks_cpu(iter, &symbols)?;
+        let folded_stacks = fold_stacks_cpu(iter, &symbolicator, &depth_limiter)?;
         vec![("cpu.svg".into(), folded_stacks)]
     } else {
-        let folded_stacks = fold_stacks_memory(iter, &symbols)?;
+        let folded_stacks = fold_stacks_memory(iter, &symbolicator, &depth_limiter)?;
         folded_stacks
             .into_iter()
             .map(|(name, stacks)| (format!("mem-{name}.svg"), stacks))
             .collect()
     };
+    let out_of_range = symbolicator.out_of_range_count();
+    if out_of_range > 0 {
+        eprintln!(
+            "warning: {out_of_range} sampled address(es) fell outside every PT_LOAD range after applying --load-offset; double-check the offset"
+        );
+    }
+    let clamped_stacks = depth_limiter.clamped_stacks();
+    if clamped_stacks > 0 {
+        eprintln!(
+            "warning: {clamped_stacks} stack(s) recorded more than {max_depth} frames and were truncated; see --max-depth"
+        );
+    }
 
     // Produce flamegraphs
     for (output, stacks) in graphs {
-        // Run flamegraph
-        let mut cmd = Command::new("FlameGraph/flamegraph.pl");
-        if alloc {
-            cmd.args(&["--colors", "mem"]);
-        }
-        cmd.stdin(Stdio::piped());
-        // Redirect output to file
-        let file = File::create(output)?;
-        cmd.stdout(file);
-        // Run
-        let child = cmd.spawn()?;
-        // Serialize output
-        let mut writer = BufWriter::new(child.stdin.unwrap());
-        for (frames, count) in stacks {
-            let buff = frames.into_iter().rev().intersperse(";");
-            for b in buff {
-                write!(writer, "{b}")?;
+        let lines = serialize_folded_stacks(&stacks);
+        if stdout_mode {
+            for line in &lines {
+                println!("{line}");
             }
-            writeln!(writer, " {count}")?;
+            continue;
+        }
+        let mut opts = flamegraph::Options::default();
+        if alloc {
+            opts.colors = Palette::Basic(BasicPalette::Mem);
+        }
+        let file = File::create(&output)?;
+        if let Err(e) = flamegraph::from_lines(&mut opts, lines.iter().map(String::as_str), file) {
+            eprintln!("Could not render flamegraph for {output}: {e}");
+            exit(1);
         }
     }
 